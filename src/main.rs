@@ -1,15 +1,22 @@
 use anyhow::Context;
 use clap::Parser;
+use crossbeam_channel::bounded;
 use indoc::indoc;
 use mail_parser::Address;
 use mail_parser::DateTime;
 use mail_parser::Message;
 use rusqlite::Connection;
+use rusqlite::OptionalExtension;
 use rusqlite_migration::Migrations;
 use rusqlite_migration::M;
+use std::collections::HashMap;
+use std::collections::HashSet;
 use std::path::PathBuf;
+use std::thread;
 
 mod report;
+mod search;
+mod thread_id;
 
 #[derive(Parser, Debug)]
 struct Args {
@@ -21,6 +28,7 @@ struct Args {
 enum Command {
     Index(IndexCommand),
     Report(report::ReportCommand),
+    Export(ExportCommand),
 }
 
 /// Convert mbox file into sqlite
@@ -30,6 +38,26 @@ struct IndexCommand {
     mbox: PathBuf,
     /// Path where sqlite file is written
     db: PathBuf,
+    /// Wipe and fully re-parse the database instead of incrementally skipping messages that are
+    /// already present (matched by Message-ID)
+    #[arg(long)]
+    reindex: bool,
+}
+
+/// Write one of the report's aggregations to stdout or a file, without starting the server
+#[derive(Parser, Debug)]
+struct ExportCommand {
+    /// Path to sqlite file previously created with `index` command
+    db: PathBuf,
+    /// Aggregation to export
+    #[arg(long, value_enum)]
+    group: report::ExportGroup,
+    /// Output format
+    #[arg(long, value_enum, default_value = "json")]
+    format: report::ExportFormat,
+    /// Write to this file instead of stdout
+    #[arg(long)]
+    output: Option<PathBuf>,
 }
 
 const CREATE_MAIL_TABLE: &str = indoc! {"
@@ -48,20 +76,73 @@ CREATE TABLE labels (
 ) WITHOUT ROWID;
 "};
 
+const ADD_THREAD_COLUMNS: &str = indoc! {"
+ALTER TABLE mail ADD COLUMN thread_id TEXT;
+
+CREATE TABLE threads (
+    thread_id TEXT PRIMARY KEY,
+    subject TEXT
+);
+"};
+
+const CREATE_ATTACHMENTS_TABLE: &str = indoc! {"
+CREATE TABLE attachments (
+    mail_rowid INTEGER,
+    content_type TEXT,
+    filename TEXT,
+    size INTEGER
+);
+"};
+
+const ADD_MESSAGE_ID_COLUMN: &str = indoc! {"
+ALTER TABLE mail ADD COLUMN message_id TEXT;
+
+CREATE UNIQUE INDEX idx_mail_message_id ON mail (message_id);
+"};
+
+const ADD_REFERENCES_RAW_COLUMN: &str = indoc! {"
+ALTER TABLE mail ADD COLUMN references_raw TEXT;
+"};
+
+/// Depth of the raw-message and parsed-message channels between the distributor, the parser
+/// pool, and the single writer thread.
+const CHANNEL_CAPACITY: usize = 256;
+
+/// A fully-parsed, fully-owned message ready to be handed to the writer thread. Building this
+/// off the writer thread is what lets parsing happen in parallel across a pool of workers.
+struct ParsedMail {
+    size: usize,
+    from_address: String,
+    date: i64,
+    raw_date: Option<String>,
+    subject: String,
+    labels: Vec<String>,
+    body: String,
+    message_id: Option<String>,
+    in_reply_to: Vec<String>,
+    references: Vec<String>,
+    attachments: Vec<AttachmentPart>,
+}
+
+/// A single MIME part of a message, recorded so the report can break down storage by
+/// content-type or attachment extension instead of just sender/label.
+struct AttachmentPart {
+    content_type: String,
+    filename: Option<String>,
+    size: usize,
+}
+
 fn main() -> anyhow::Result<()> {
     let args = Args::parse();
     match args.command {
         Command::Index(c) => c.run(),
         Command::Report(c) => c.run(),
+        Command::Export(c) => c.run(),
     }
 }
 
 impl IndexCommand {
     fn run(self) -> anyhow::Result<()> {
-        let default_date = DateTime::from_timestamp(0);
-
-        let parser = mail_parser::MessageParser::new();
-
         let mf = mbox_reader::MboxFile::from_file(&self.mbox)
             .with_context(|| format!("failed to open mbox {}", self.mbox.display()))?;
 
@@ -73,76 +154,365 @@ impl IndexCommand {
         conn.pragma_update(None, "synchronous", "OFF")
             .context("failed to set synchronous=OFF")?;
 
-        let migrations = Migrations::new(vec![M::up(CREATE_MAIL_TABLE)]);
+        let migrations = Migrations::new(vec![
+            M::up(CREATE_MAIL_TABLE),
+            M::up(ADD_THREAD_COLUMNS),
+            M::up(CREATE_ATTACHMENTS_TABLE),
+            M::up(ADD_MESSAGE_ID_COLUMN),
+            M::up(ADD_REFERENCES_RAW_COLUMN),
+        ]);
         migrations
             .to_latest(&mut conn)
             .context("failed to migrate schema")?;
 
-        let mut insert_mail = conn.prepare(indoc! {"
-            INSERT INTO mail (size, from_address, date, raw_date, subject)
-            VALUES (?, ?, ?, ?, ?)
-        "})?;
-
-        let mut insert_label = conn.prepare(indoc! {"
-            INSERT INTO labels (mail_rowid, label)
-            VALUES (?, ?)
-        "})?;
-
-        // On an explicit reindex, delete any existing rows.
-        conn.execute("DELETE FROM mail", ())?;
-        conn.execute("DELETE FROM labels", ())?;
-
-        // Speedups:
-        // - transaction(s)
-        // - prepared statements
-        // - create indices at the end
-
-        conn.execute("BEGIN", ())?;
-
-        for mail in mf.iter() {
-            let Some(raw_message) = mail.message() else {
-                println!("No message: {:#?}", mail.start().as_str());
-                continue;
-            };
-            let Some(message) = parser.parse_headers(raw_message) else {
-                println!("Unable to parse message");
-                continue;
-            };
-
-            // TODO: Should we factor in the mbox `from` line?
-            // message_size + mail.start().as_str().len()
-
-            let message_size = raw_message.len();
-            let from_address = find_from_address(&message).unwrap_or("(unknown sender)");
-            let date = message.date().unwrap_or(&default_date);
-            let date_raw = message.header_raw("Date");
-            let subject = message.subject().unwrap_or("(no subject)");
-
-            let labels = if let Some(gmail_labels) = message.header_raw("X-Gmail-Labels") {
-                gmail_labels
-                    .split(',')
-                    .map(|lbl| lbl.trim().replace(['\n', '\r'], ""))
-                    .collect()
-            } else {
-                vec!["Unlabeled".to_owned()]
-            };
-
-            insert_mail.execute((
-                message_size,
-                from_address,
-                date.to_timestamp(),
-                date_raw,
-                subject,
-            ))?;
-
-            let mail_rowid = conn.last_insert_rowid();
-
-            for label in labels {
-                insert_label.execute((mail_rowid, label))?;
+        // On an explicit --reindex, wipe any existing rows and the search index, and reparse
+        // everything from scratch. Otherwise messages are matched by Message-ID so re-running on
+        // an updated or overlapping mbox is cheap.
+        if self.reindex {
+            conn.execute("DELETE FROM mail", ())?;
+            conn.execute("DELETE FROM labels", ())?;
+            conn.execute("DELETE FROM threads", ())?;
+            conn.execute("DELETE FROM attachments", ())?;
+
+            let index_dir = search::index_dir_for(&self.db);
+            if index_dir.exists() {
+                std::fs::remove_dir_all(&index_dir).with_context(|| {
+                    format!("failed to remove search index dir {}", index_dir.display())
+                })?;
             }
         }
 
-        conn.execute("COMMIT", ())?;
+        let search_index = search::SearchIndex::open_or_create(&self.db)
+            .context("failed to open search index")?;
+
+        let num_workers = thread::available_parallelism().map_or(4, |n| n.get());
+        let reindex = self.reindex;
+
+        let (raw_tx, raw_rx) = bounded::<&[u8]>(CHANNEL_CAPACITY);
+        let (parsed_tx, parsed_rx) = bounded::<ParsedMail>(CHANNEL_CAPACITY);
+
+        let mut read_count = 0u64;
+
+        thread::scope(|scope| -> anyhow::Result<()> {
+            // Single writer thread: owns the connection and the search index writer, and is the
+            // only thing that ever calls `conn.last_insert_rowid()`, which preserves the
+            // rowid -> labels invariant that concurrent inserts would otherwise break.
+            let writer_handle = scope.spawn(move || -> anyhow::Result<(u64, u64)> {
+                let mut find_by_message_id = conn.prepare(
+                    "SELECT _rowid_ FROM mail WHERE message_id = ?",
+                )?;
+                let mut insert_mail = conn.prepare(indoc! {"
+                    INSERT INTO mail (size, from_address, date, raw_date, subject, message_id, references_raw)
+                    VALUES (?, ?, ?, ?, ?, ?, ?)
+                "})?;
+                let mut insert_label = conn.prepare(indoc! {"
+                    INSERT OR IGNORE INTO labels (mail_rowid, label)
+                    VALUES (?, ?)
+                "})?;
+                let mut insert_attachment = conn.prepare(indoc! {"
+                    INSERT INTO attachments (mail_rowid, content_type, filename, size)
+                    VALUES (?, ?, ?, ?)
+                "})?;
+                let mut search_writer = search_index.writer()?;
+
+                // Union-find over Message-ID/In-Reply-To/References, used to assign each message
+                // a thread_id once every message has been seen. Each entry also carries the
+                // thread_id the row already has on disk (`None` for rows inserted this run), so
+                // the write-back pass below can skip rows whose canonical thread_id didn't
+                // actually change instead of rewriting the whole table every run.
+                let mut threads = thread_id::ThreadIndex::new();
+                let mut pending_threads: Vec<(i64, usize, String, i64, Option<String>)> =
+                    Vec::new();
+
+                // On an incremental run, messages already in the database are skipped below and
+                // never fed into `threads`, which would otherwise fragment a thread across runs
+                // whenever a newly-indexed message references an already-indexed one. Rebuild the
+                // union-find from what's already on disk first, so new messages still union with
+                // their existing thread. This still has to scan every row to reconstruct set
+                // membership (the union-find forest itself isn't persisted), but the write-back
+                // pass only touches rows whose canonical thread_id actually moved.
+                if !reindex {
+                    let mut existing = conn.prepare(
+                        "SELECT _rowid_, message_id, references_raw, subject, date, thread_id FROM mail",
+                    )?;
+                    let mut rows = existing.query(())?;
+                    while let Some(row) = rows.next()? {
+                        let mail_rowid: i64 = row.get(0)?;
+                        let message_id: Option<String> = row.get(1)?;
+                        let references_raw: Option<String> = row.get(2)?;
+                        let subject: String = row.get(3)?;
+                        let date: i64 = row.get(4)?;
+                        let old_thread_id: Option<String> = row.get(5)?;
+
+                        let own_id =
+                            message_id.unwrap_or_else(|| format!("synthetic:{mail_rowid}"));
+                        let own_index = threads.get_or_insert(&own_id);
+                        let references = references_raw
+                            .as_deref()
+                            .map(thread_id::parse_message_ids)
+                            .unwrap_or_default();
+                        for reference_id in references {
+                            let reference_index = threads.get_or_insert(&reference_id);
+                            threads.union(own_index, reference_index);
+                        }
+                        pending_threads.push((mail_rowid, own_index, subject, date, old_thread_id));
+                    }
+                    drop(existing);
+                }
+
+                conn.execute("BEGIN", ())?;
+
+                let mut inserted = 0u64;
+                let mut skipped = 0u64;
+                for parsed in parsed_rx {
+                    let existing_rowid = if reindex {
+                        None
+                    } else if let Some(message_id) = &parsed.message_id {
+                        find_by_message_id
+                            .query_row([message_id], |row| row.get(0))
+                            .optional()?
+                    } else {
+                        None
+                    };
+
+                    if let Some(mail_rowid) = existing_rowid {
+                        // Already indexed (matched by Message-ID): only merge in any new labels,
+                        // e.g. from re-downloading an overlapping Gmail Takeout export.
+                        for label in parsed.labels {
+                            insert_label.execute((mail_rowid, label))?;
+                        }
+                        skipped += 1;
+                        continue;
+                    }
+
+                    let references_raw = parsed
+                        .in_reply_to
+                        .iter()
+                        .chain(parsed.references.iter())
+                        .cloned()
+                        .collect::<Vec<_>>()
+                        .join(" ");
+
+                    insert_mail.execute((
+                        parsed.size,
+                        &parsed.from_address,
+                        parsed.date,
+                        &parsed.raw_date,
+                        &parsed.subject,
+                        &parsed.message_id,
+                        &references_raw,
+                    ))?;
+
+                    let mail_rowid = conn.last_insert_rowid();
+
+                    for label in parsed.labels {
+                        insert_label.execute((mail_rowid, label))?;
+                    }
+
+                    for attachment in parsed.attachments {
+                        insert_attachment.execute((
+                            mail_rowid,
+                            attachment.content_type,
+                            attachment.filename,
+                            attachment.size,
+                        ))?;
+                    }
+
+                    search_writer.add_document(
+                        mail_rowid as u64,
+                        &parsed.from_address,
+                        &parsed.subject,
+                        &parsed.body,
+                        parsed.date,
+                    )?;
+
+                    let own_id = parsed
+                        .message_id
+                        .clone()
+                        .unwrap_or_else(|| format!("synthetic:{mail_rowid}"));
+                    let own_index = threads.get_or_insert(&own_id);
+                    for reference_id in parsed.in_reply_to.iter().chain(parsed.references.iter()) {
+                        let reference_index = threads.get_or_insert(reference_id);
+                        threads.union(own_index, reference_index);
+                    }
+                    pending_threads.push((mail_rowid, own_index, parsed.subject, parsed.date, None));
+
+                    inserted += 1;
+                }
+
+                // Now that every message has been unioned into its thread, assign each row its
+                // final thread_id and work out each thread's display subject (the subject of its
+                // earliest message, with Re:/Fwd: prefixes stripped). Rows whose canonical
+                // thread_id didn't move from what's already on disk are left alone, so an
+                // incremental run's writes scale with how much actually changed, not with the
+                // size of the whole archive.
+                let mut update_thread_id =
+                    conn.prepare("UPDATE mail SET thread_id = ? WHERE _rowid_ = ?")?;
+                let mut thread_subjects: HashMap<String, (i64, String)> = HashMap::new();
+                let mut dirty_thread_ids: HashSet<String> = HashSet::new();
+                for (mail_rowid, own_index, subject, date, old_thread_id) in pending_threads {
+                    let canonical_thread_id = threads.thread_id(own_index);
+                    if old_thread_id.as_deref() != Some(canonical_thread_id.as_str()) {
+                        update_thread_id.execute((&canonical_thread_id, mail_rowid))?;
+                        dirty_thread_ids.insert(canonical_thread_id.clone());
+                    }
+
+                    let display_subject = thread_id::strip_reply_prefixes(&subject).to_owned();
+                    thread_subjects
+                        .entry(canonical_thread_id)
+                        .and_modify(|(best_date, best_subject)| {
+                            if date < *best_date {
+                                *best_date = date;
+                                *best_subject = display_subject.clone();
+                            }
+                        })
+                        .or_insert((date, display_subject));
+                }
+                drop(update_thread_id);
+
+                let mut insert_thread =
+                    conn.prepare("INSERT OR REPLACE INTO threads (thread_id, subject) VALUES (?, ?)")?;
+                for (canonical_thread_id, (_date, subject)) in thread_subjects {
+                    if dirty_thread_ids.contains(&canonical_thread_id) {
+                        insert_thread.execute((canonical_thread_id, subject))?;
+                    }
+                }
+                drop(insert_thread);
+
+                drop(find_by_message_id);
+                drop(insert_mail);
+                drop(insert_label);
+                drop(insert_attachment);
+                conn.execute("COMMIT", ())?;
+                search_writer.finish()?;
+
+                Ok((inserted, skipped))
+            });
+
+            // Parser pool: each worker pulls raw message slices off `raw_rx` and turns them into
+            // fully-owned `ParsedMail` structs, so the only thing the writer thread ever does is
+            // sqlite/tantivy I/O.
+            let worker_handles: Vec<_> = (0..num_workers)
+                .map(|_| {
+                    let raw_rx = raw_rx.clone();
+                    let parsed_tx = parsed_tx.clone();
+                    scope.spawn(move || {
+                        let default_date = DateTime::from_timestamp(0);
+                        let parser = mail_parser::MessageParser::new();
+                        for raw_message in raw_rx {
+                            let Some(message) = parser.parse(raw_message) else {
+                                println!("Unable to parse message");
+                                continue;
+                            };
+
+                            // TODO: Should we factor in the mbox `from` line?
+                            // message_size + mail.start().as_str().len()
+
+                            let size = raw_message.len();
+                            let from_address = find_from_address(&message)
+                                .unwrap_or("(unknown sender)")
+                                .to_owned();
+                            let date = message.date().unwrap_or(&default_date).to_timestamp();
+                            let raw_date = message.header_raw("Date").map(str::to_owned);
+                            let subject = message.subject().unwrap_or("(no subject)").to_owned();
+
+                            let labels = if let Some(gmail_labels) =
+                                message.header_raw("X-Gmail-Labels")
+                            {
+                                gmail_labels
+                                    .split(',')
+                                    .map(|lbl| lbl.trim().replace(['\n', '\r'], ""))
+                                    .collect()
+                            } else {
+                                vec!["Unlabeled".to_owned()]
+                            };
+
+                            let body = search::extract_body_text(&message);
+
+                            let message_id = message
+                                .header_raw("Message-ID")
+                                .and_then(|raw| thread_id::parse_message_ids(raw).into_iter().next());
+                            let in_reply_to = message
+                                .header_raw("In-Reply-To")
+                                .map(thread_id::parse_message_ids)
+                                .unwrap_or_default();
+                            let references = message
+                                .header_raw("References")
+                                .map(thread_id::parse_message_ids)
+                                .unwrap_or_default();
+
+                            let attachments = extract_attachments(&message);
+
+                            let parsed = ParsedMail {
+                                size,
+                                from_address,
+                                date,
+                                raw_date,
+                                subject,
+                                labels,
+                                body,
+                                message_id,
+                                in_reply_to,
+                                references,
+                                attachments,
+                            };
+
+                            if parsed_tx.send(parsed).is_err() {
+                                break;
+                            }
+                        }
+                    })
+                })
+                .collect();
+
+            // Drop our copies so the channels close once the distributor and workers are done.
+            drop(raw_rx);
+            drop(parsed_tx);
+
+            // Distributor: the main scope thread walks the mbox and feeds raw message slices to
+            // the parser pool.
+            for mail in mf.iter() {
+                let Some(raw_message) = mail.message() else {
+                    println!("No message: {:#?}", mail.start().as_str());
+                    continue;
+                };
+                read_count += 1;
+                if raw_tx.send(raw_message).is_err() {
+                    break;
+                }
+            }
+            drop(raw_tx);
+
+            for handle in worker_handles {
+                handle.join().expect("parser worker panicked");
+            }
+
+            let (inserted, skipped) = writer_handle.join().expect("writer thread panicked")?;
+            println!("Read: {read_count}, Inserted: {inserted}, Skipped: {skipped}");
+
+            Ok(())
+        })
+    }
+}
+
+impl ExportCommand {
+    fn run(self) -> anyhow::Result<()> {
+        let conn = Connection::open_with_flags(&self.db, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .with_context(|| format!("failed to open db {}", self.db.display()))?;
+
+        let rows = report::aggregate_rows(&conn, self.group, &report::Filters::default())
+            .context("failed to compute aggregation")?;
+
+        let text = match self.format {
+            report::ExportFormat::Json => serde_json::to_string_pretty(&rows)?,
+            report::ExportFormat::Csv => report::rows_to_csv(&rows),
+        };
+
+        match self.output {
+            Some(path) => std::fs::write(&path, text)
+                .with_context(|| format!("failed to write {}", path.display()))?,
+            None => println!("{text}"),
+        }
 
         Ok(())
     }
@@ -156,3 +526,27 @@ fn find_from_address<'a>(message: &'a Message<'a>) -> Option<&'a str> {
     };
     addr.address.as_deref()
 }
+
+/// Walks every MIME part of `message` (text, HTML, and attachments alike) so the report can show
+/// what's actually taking up space, not just who sent it.
+fn extract_attachments(message: &Message) -> Vec<AttachmentPart> {
+    message
+        .parts
+        .iter()
+        .map(|part| {
+            let content_type = part
+                .content_type()
+                .map(|ct| match ct.subtype() {
+                    Some(subtype) => format!("{}/{}", ct.ctype(), subtype),
+                    None => ct.ctype().to_owned(),
+                })
+                .unwrap_or_else(|| "application/octet-stream".to_owned());
+
+            AttachmentPart {
+                content_type,
+                filename: part.attachment_name().map(str::to_owned),
+                size: part.len(),
+            }
+        })
+        .collect()
+}
@@ -18,6 +18,8 @@ use std::sync::Mutex;
 use tokio::runtime::Runtime;
 use url::Url;
 
+use crate::search::SearchIndex;
+
 const PORT: u16 = 31200;
 
 #[derive(Debug, Parser)]
@@ -37,6 +39,9 @@ impl ReportCommand {
             rusqlite::Connection::open_with_flags(&self.db, OpenFlags::SQLITE_OPEN_READ_ONLY)
                 .with_context(|| format!("failed to open db {}", self.db.display()))?;
 
+        let search_index = SearchIndex::open_or_create(&self.db)
+            .context("failed to open search index")?;
+
         let db = self
             .db
             .file_name()
@@ -56,6 +61,7 @@ impl ReportCommand {
             total_size,
             tera,
             conn: Mutex::new(conn),
+            search_index,
         });
 
         // Bind to a local address.
@@ -63,6 +69,8 @@ impl ReportCommand {
             App::new()
                 .app_data(web::Data::new(state.clone()))
                 .service(index)
+                .service(search)
+                .service(export)
         })
         .bind(("127.0.0.1", PORT))?;
 
@@ -77,6 +85,7 @@ struct AppState {
     total_size: u64,
     tera: tera::Tera,
     conn: Mutex<rusqlite::Connection>,
+    search_index: SearchIndex,
 }
 
 #[derive(Serialize)]
@@ -100,12 +109,16 @@ struct Mail {
     raw_date: Option<String>,
 }
 
-#[derive(Clone, Deserialize)]
-struct Filters {
+#[derive(Clone, Default, Deserialize)]
+pub struct Filters {
     label: Option<String>,
     year: Option<String>,
     domain: Option<String>,
     address: Option<String>,
+    thread: Option<String>,
+    content_type: Option<String>,
+    attachment_extension: Option<String>,
+    month: Option<String>,
 }
 
 impl Filters {
@@ -116,6 +129,14 @@ impl Filters {
         self.year.as_ref().map(|s| qp.append_pair("year", s));
         self.domain.as_ref().map(|s| qp.append_pair("domain", s));
         self.address.as_ref().map(|s| qp.append_pair("address", s));
+        self.thread.as_ref().map(|s| qp.append_pair("thread", s));
+        self.content_type
+            .as_ref()
+            .map(|s| qp.append_pair("content_type", s));
+        self.attachment_extension
+            .as_ref()
+            .map(|s| qp.append_pair("attachment_extension", s));
+        self.month.as_ref().map(|s| qp.append_pair("month", s));
         drop(qp);
         return base.as_str().strip_prefix("fake:").unwrap().to_owned();
     }
@@ -125,6 +146,10 @@ impl Filters {
             || self.year.is_some()
             || self.domain.is_some()
             || self.address.is_some()
+            || self.thread.is_some()
+            || self.content_type.is_some()
+            || self.attachment_extension.is_some()
+            || self.month.is_some()
     }
 
     fn clause(&self) -> String {
@@ -139,6 +164,16 @@ impl Filters {
                 "mail._rowid_",
             ]);
         }
+        if self.content_type.is_some() || self.attachment_extension.is_some() {
+            words.extend_from_slice(&[
+                "JOIN",
+                "attachments",
+                "ON",
+                "attachments.mail_rowid",
+                "=",
+                "mail._rowid_",
+            ]);
+        }
         let mut add_clause = |column, value: Option<&String>| {
             if value.is_some() {
                 words.push(if words.is_empty() { "WHERE" } else { "AND" });
@@ -155,6 +190,16 @@ impl Filters {
             self.domain.as_ref(),
         );
         add_clause("from_address", self.address.as_ref());
+        add_clause("mail.thread_id", self.thread.as_ref());
+        add_clause("attachments.content_type", self.content_type.as_ref());
+        add_clause(
+            "lower(substr(attachments.filename, instr(attachments.filename, '.') + 1))",
+            self.attachment_extension.as_ref(),
+        );
+        add_clause(
+            "strftime('%Y-%m', datetime(date, 'unixepoch'))",
+            self.month.as_ref(),
+        );
         words.join(" ")
     }
 
@@ -169,6 +214,10 @@ impl Filters {
         add_param(self.year.as_ref());
         add_param(self.domain.as_ref());
         add_param(self.address.as_ref());
+        add_param(self.thread.as_ref());
+        add_param(self.content_type.as_ref());
+        add_param(self.attachment_extension.as_ref());
+        add_param(self.month.as_ref());
         params
     }
 }
@@ -218,6 +267,46 @@ async fn index(data: web::Data<Arc<AppState>>, query: web::Query<Filters>) -> im
             .to_url(),
         });
     }
+    if let Some(key) = filters.thread.as_ref() {
+        active_filters.push(ActiveFilter {
+            key: key.to_owned(),
+            remove_url: Filters {
+                thread: None,
+                ..filters.clone()
+            }
+            .to_url(),
+        });
+    }
+    if let Some(key) = filters.content_type.as_ref() {
+        active_filters.push(ActiveFilter {
+            key: key.to_owned(),
+            remove_url: Filters {
+                content_type: None,
+                ..filters.clone()
+            }
+            .to_url(),
+        });
+    }
+    if let Some(key) = filters.attachment_extension.as_ref() {
+        active_filters.push(ActiveFilter {
+            key: key.to_owned(),
+            remove_url: Filters {
+                attachment_extension: None,
+                ..filters.clone()
+            }
+            .to_url(),
+        });
+    }
+    if let Some(key) = filters.month.as_ref() {
+        active_filters.push(ActiveFilter {
+            key: key.to_owned(),
+            remove_url: Filters {
+                month: None,
+                ..filters.clone()
+            }
+            .to_url(),
+        });
+    }
 
     let options = humansize::FormatSizeOptions::from(humansize::DECIMAL).decimal_places(2);
 
@@ -305,6 +394,44 @@ async fn index(data: web::Data<Arc<AppState>>, query: web::Query<Filters>) -> im
         }
     }
 
+    // Finer-grained than by_year: a month-by-month histogram. When a year filter is active,
+    // filters.clause() already restricts this to that year, giving a drill-down from the yearly
+    // totals into the specific months where mail volume spiked.
+    let mut by_month = Vec::new();
+    if filters.month.is_none() {
+        let mut stmt = conn
+            .prepare(&format!(
+                indoc! {r#"
+                    SELECT strftime("%Y-%m", datetime(date, 'unixepoch')) as month, sum(size) as total_size
+                    FROM mail
+                    {}
+                    GROUP BY month
+                    ORDER BY month ASC
+                "#},
+                filters.clause()
+            ))
+            .expect("must be valid syntax");
+
+        let mut rows = stmt
+            .query(filters.params().as_slice())
+            .expect("query failed");
+        while let Some(row) = rows.next().expect("next failed") {
+            let month: String = row.get(0).expect("expected column 0");
+            by_month.push(ByString {
+                key: month.clone(),
+                size: humansize::format_size(
+                    row.get::<usize, u64>(1).expect("expected column 1"),
+                    options,
+                ),
+                filter_url: Filters {
+                    month: Some(month),
+                    ..filters.clone()
+                }
+                .to_url(),
+            });
+        }
+    }
+
     let mut by_domain = Vec::new();
     if filters.domain.is_none() {
         let mut stmt = conn
@@ -377,6 +504,125 @@ async fn index(data: web::Data<Arc<AppState>>, query: web::Query<Filters>) -> im
         }
     }
 
+    let mut by_thread = Vec::new();
+    if filters.thread.is_none() {
+        let mut stmt = conn
+            .prepare(&format!(
+                indoc! {r#"
+                    SELECT mail.thread_id, COALESCE(threads.subject, mail.thread_id), sum(size) as total_size
+                    FROM mail
+                    LEFT JOIN threads ON threads.thread_id = mail.thread_id
+                    {}
+                    GROUP BY mail.thread_id
+                    ORDER BY total_size DESC
+                    LIMIT 30
+                "#},
+                filters.clause()
+            ))
+            .expect("must be valid syntax");
+
+        let mut rows = stmt
+            .query(filters.params().as_slice())
+            .expect("query failed");
+        while let Some(row) = rows.next().expect("next failed") {
+            let thread_id: String = row.get(0).expect("expected column 0");
+            let subject: String = row.get(1).expect("expected column 1");
+            by_thread.push(ByString {
+                key: subject,
+                size: humansize::format_size(
+                    row.get::<usize, u64>(2).expect("expected column 2"),
+                    options,
+                ),
+                filter_url: Filters {
+                    thread: Some(thread_id),
+                    ..filters.clone()
+                }
+                .to_url(),
+            });
+        }
+    }
+
+    let mut by_content_type = Vec::new();
+    if filters.content_type.is_none() && filters.attachment_extension.is_none() {
+        let mut stmt = conn
+            .prepare(&format!(
+                indoc! {r#"
+                    SELECT attachments.content_type, sum(attachments.size) as total_size
+                    FROM attachments
+                    JOIN mail ON mail._rowid_ = attachments.mail_rowid
+                    {}
+                    GROUP BY attachments.content_type
+                    ORDER BY total_size DESC
+                    LIMIT 30
+                "#},
+                filters.clause()
+            ))
+            .expect("must be valid syntax");
+
+        let mut rows = stmt
+            .query(filters.params().as_slice())
+            .expect("query failed");
+        while let Some(row) = rows.next().expect("next failed") {
+            let content_type: String = row.get(0).expect("expected column 0");
+            by_content_type.push(ByString {
+                key: content_type.clone(),
+                size: humansize::format_size(
+                    row.get::<usize, u64>(1).expect("expected column 1"),
+                    options,
+                ),
+                filter_url: Filters {
+                    content_type: Some(content_type),
+                    ..filters.clone()
+                }
+                .to_url(),
+            });
+        }
+    }
+
+    let mut by_attachment_extension = Vec::new();
+    if filters.content_type.is_none() && filters.attachment_extension.is_none() {
+        let mut stmt = conn
+            .prepare(&format!(
+                indoc! {r#"
+                    SELECT CASE WHEN instr(attachments.filename, '.') > 0
+                               THEN lower(substr(attachments.filename, instr(attachments.filename, '.') + 1))
+                           END as extension,
+                           sum(attachments.size) as total_size
+                    FROM attachments
+                    JOIN mail ON mail._rowid_ = attachments.mail_rowid
+                    {}
+                    GROUP BY extension
+                    ORDER BY total_size DESC
+                    LIMIT 30
+                "#},
+                filters.clause()
+            ))
+            .expect("must be valid syntax");
+
+        let mut rows = stmt
+            .query(filters.params().as_slice())
+            .expect("query failed");
+        while let Some(row) = rows.next().expect("next failed") {
+            let extension: Option<String> = row.get(0).expect("expected column 0");
+            // Attachments with no filename, or no `.` in the filename, fall out as NULL.
+            let Some(extension) = extension.filter(|e| !e.is_empty()) else {
+                continue;
+            };
+            by_attachment_extension.push(ByString {
+                key: extension.clone(),
+                size: humansize::format_size(
+                    row.get::<usize, u64>(1).expect("expected column 1"),
+                    options,
+                ),
+                filter_url: Filters {
+                    attachment_extension: Some(extension),
+                    ..filters.clone()
+                }
+                .to_url(),
+            });
+        }
+    }
+
     let mut stmt = conn
         .prepare(&format!(
             indoc! {r#"
@@ -419,8 +665,224 @@ async fn index(data: web::Data<Arc<AppState>>, query: web::Query<Filters>) -> im
     context.insert("active_filters", &active_filters);
     context.insert("by_label", &by_label);
     context.insert("by_year", &by_year);
+    context.insert("by_month", &by_month);
     context.insert("by_domain", &by_domain);
     context.insert("by_address", &by_address);
+    context.insert("by_thread", &by_thread);
+    context.insert("by_content_type", &by_content_type);
+    context.insert("by_attachment_extension", &by_attachment_extension);
     context.insert("top_mail", &top_mail);
     HttpResponse::Ok().body(data.tera.render("index", &context).unwrap())
 }
+
+/// Maximum number of full-text search hits considered before applying `Filters`.
+const SEARCH_CANDIDATES: usize = 500;
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    q: String,
+    #[serde(flatten)]
+    filters: Filters,
+}
+
+#[derive(Serialize)]
+struct SearchResults {
+    query: String,
+    mail: Vec<Mail>,
+}
+
+#[get("/search")]
+async fn search(
+    data: web::Data<Arc<AppState>>,
+    query: web::Query<SearchQuery>,
+) -> impl Responder {
+    let SearchQuery { q, filters } = query.into_inner();
+
+    let rowids = match data.search_index.search(&q, SEARCH_CANDIDATES) {
+        Ok(rowids) => rowids,
+        Err(err) => {
+            return HttpResponse::BadRequest().body(format!("invalid search query: {err}"));
+        }
+    };
+
+    let options = humansize::FormatSizeOptions::from(humansize::DECIMAL).decimal_places(2);
+    let conn = data.conn.lock().unwrap();
+
+    let mut mail_by_rowid = std::collections::HashMap::new();
+    if !rowids.is_empty() {
+        let placeholders = vec!["?"; rowids.len()].join(", ");
+        let conjunction = if filters.clause().is_empty() {
+            "WHERE"
+        } else {
+            "AND"
+        };
+        let sql = format!(
+            "SELECT mail._rowid_, from_address, size, subject, raw_date FROM mail {} {} mail._rowid_ IN ({})",
+            filters.clause(),
+            conjunction,
+            placeholders
+        );
+
+        let mut params = filters.params();
+        let rowid_params: Vec<i64> = rowids.iter().map(|r| *r as i64).collect();
+        for rowid in &rowid_params {
+            params.push(rowid as &dyn ToSql);
+        }
+
+        let mut stmt = match conn.prepare(&sql) {
+            Ok(stmt) => stmt,
+            Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+        };
+        let mut rows = stmt.query(params.as_slice()).expect("query failed");
+        while let Some(row) = rows.next().expect("next failed") {
+            let rowid: i64 = row.get(0).expect("expected column 0");
+            mail_by_rowid.insert(
+                rowid as u64,
+                Mail {
+                    from: row.get(1).expect("expected column 1"),
+                    size: humansize::format_size(
+                        row.get::<usize, u64>(2).expect("expected column 2"),
+                        options,
+                    ),
+                    subject: row.get(3).expect("expected column 3"),
+                    raw_date: row.get(4).expect("expected column 4"),
+                },
+            );
+        }
+    }
+
+    let mail = rowids
+        .into_iter()
+        .filter_map(|rowid| mail_by_rowid.remove(&rowid))
+        .collect();
+
+    HttpResponse::Ok().json(SearchResults { query: q, mail })
+}
+
+/// Which column the `/export` endpoint and `export` subcommand group by. Mirrors the
+/// `by_label`/`by_year`/`by_domain`/`by_address` aggregations rendered on the report page.
+#[derive(Clone, Copy, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportGroup {
+    Domain,
+    Address,
+    Year,
+    Label,
+}
+
+#[derive(Clone, Copy, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Json,
+    Csv,
+}
+
+#[derive(Serialize)]
+pub struct ExportRow {
+    pub key: String,
+    pub size: u64,
+}
+
+/// Runs the aggregation named by `group` (composed with `filters`) and returns raw, unformatted
+/// rows, suitable for serializing to JSON/CSV instead of rendering into a template.
+pub fn aggregate_rows(
+    conn: &rusqlite::Connection,
+    group: ExportGroup,
+    filters: &Filters,
+) -> anyhow::Result<Vec<ExportRow>> {
+    let (from_clause, group_expr) = match group {
+        ExportGroup::Domain => (
+            "mail",
+            "substr(from_address, instr(from_address, '@') + 1)",
+        ),
+        ExportGroup::Address => ("mail", "from_address"),
+        ExportGroup::Year => ("mail", "strftime('%Y', datetime(date, 'unixepoch'))"),
+        // `filters.clause()` already joins `labels` when `filters.label` is set, so only add our
+        // own join here when that won't happen, to avoid a double self-join on `labels`.
+        ExportGroup::Label if filters.label.is_some() => ("mail", "labels.label"),
+        ExportGroup::Label => (
+            "labels JOIN mail ON labels.mail_rowid = mail._rowid_",
+            "labels.label",
+        ),
+    };
+
+    let sql = format!(
+        "SELECT {group_expr}, sum(size) as total_size FROM {from_clause} {filter_clause} GROUP BY {group_expr} ORDER BY total_size DESC",
+        group_expr = group_expr,
+        from_clause = from_clause,
+        filter_clause = filters.clause(),
+    );
+
+    let mut stmt = conn.prepare(&sql)?;
+    let mut rows = stmt.query(filters.params().as_slice())?;
+
+    let mut result = Vec::new();
+    while let Some(row) = rows.next()? {
+        result.push(ExportRow {
+            key: row.get(0)?,
+            size: row.get(1)?,
+        });
+    }
+    Ok(result)
+}
+
+pub fn rows_to_csv(rows: &[ExportRow]) -> String {
+    let mut out = String::from("key,size\n");
+    for row in rows {
+        out.push_str(&csv_escape(&row.key));
+        out.push(',');
+        out.push_str(&row.size.to_string());
+        out.push('\n');
+    }
+    out
+}
+
+fn csv_escape(value: &str) -> String {
+    // Values come straight from mail headers (sender address, domain, label), so a crafted
+    // value starting with `=`, `+`, `-`, or `@` must be neutralized: spreadsheet apps treat a
+    // leading one of those as a formula. Prefixing with `'` keeps it inert without changing how
+    // the value otherwise renders.
+    let value = if value.starts_with(['=', '+', '-', '@']) {
+        format!("'{value}")
+    } else {
+        value.to_owned()
+    };
+
+    if value.contains(['"', ',', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+#[derive(Deserialize)]
+struct ExportQuery {
+    format: ExportFormat,
+    group: ExportGroup,
+    #[serde(flatten)]
+    filters: Filters,
+}
+
+#[get("/export")]
+async fn export(data: web::Data<Arc<AppState>>, query: web::Query<ExportQuery>) -> impl Responder {
+    let ExportQuery {
+        format,
+        group,
+        filters,
+    } = query.into_inner();
+
+    let conn = data.conn.lock().unwrap();
+    let rows = match aggregate_rows(&conn, group, &filters) {
+        Ok(rows) => rows,
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+
+    match format {
+        ExportFormat::Json => HttpResponse::Ok()
+            .content_type("application/json")
+            .json(rows),
+        ExportFormat::Csv => HttpResponse::Ok()
+            .content_type("text/csv")
+            .body(rows_to_csv(&rows)),
+    }
+}
@@ -0,0 +1,185 @@
+use anyhow::Context;
+use std::path::Path;
+use tantivy::collector::TopDocs;
+use tantivy::directory::MmapDirectory;
+use tantivy::query::QueryParser;
+use tantivy::schema::Field;
+use tantivy::schema::Schema;
+use tantivy::schema::Value;
+use tantivy::schema::FAST;
+use tantivy::schema::INDEXED;
+use tantivy::schema::STORED;
+use tantivy::schema::TEXT;
+use tantivy::Index;
+use tantivy::IndexWriter;
+use tantivy::TantivyDocument;
+
+/// Sibling directory name, alongside the sqlite `.db` file, that holds the tantivy index.
+const INDEX_DIR_SUFFIX: &str = ".tantivy";
+
+/// Number of documents to batch before committing the writer.
+const COMMIT_BATCH: usize = 1000;
+
+#[derive(Clone, Copy)]
+pub struct SearchFields {
+    pub rowid: Field,
+    pub from_address: Field,
+    pub subject: Field,
+    pub body: Field,
+    pub date: Field,
+}
+
+pub fn build_schema() -> (Schema, SearchFields) {
+    let mut builder = Schema::builder();
+    let rowid = builder.add_u64_field("rowid", STORED);
+    let from_address = builder.add_text_field("from_address", TEXT | STORED);
+    let subject = builder.add_text_field("subject", TEXT | STORED);
+    let body = builder.add_text_field("body", TEXT);
+    let date = builder.add_i64_field("date", INDEXED | FAST);
+    (
+        builder.build(),
+        SearchFields {
+            rowid,
+            from_address,
+            subject,
+            body,
+            date,
+        },
+    )
+}
+
+/// Returns the directory tantivy's index lives in, next to `db_path`.
+pub fn index_dir_for(db_path: &Path) -> std::path::PathBuf {
+    let mut dir = db_path.as_os_str().to_owned();
+    dir.push(INDEX_DIR_SUFFIX);
+    std::path::PathBuf::from(dir)
+}
+
+pub struct SearchIndex {
+    pub index: Index,
+    pub fields: SearchFields,
+}
+
+impl SearchIndex {
+    /// Opens an existing tantivy index next to `db_path`, or creates one if missing.
+    pub fn open_or_create(db_path: &Path) -> anyhow::Result<Self> {
+        let dir = index_dir_for(db_path);
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("failed to create search index dir {}", dir.display()))?;
+
+        let (schema, fields) = build_schema();
+        let directory = MmapDirectory::open(&dir)
+            .with_context(|| format!("failed to open search index dir {}", dir.display()))?;
+        let index = Index::open_or_create(directory, schema)
+            .context("failed to open or create tantivy index")?;
+
+        Ok(SearchIndex { index, fields })
+    }
+
+    pub fn writer(&self) -> anyhow::Result<SearchWriter> {
+        let writer = self
+            .index
+            .writer(50_000_000)
+            .context("failed to create tantivy index writer")?;
+        Ok(SearchWriter {
+            writer,
+            fields: self.fields,
+            pending: 0,
+        })
+    }
+
+    /// Runs `query` over subject+body and returns matching rowids ranked by relevance (best
+    /// first).
+    pub fn search(
+        &self,
+        query: &str,
+        limit: usize,
+    ) -> anyhow::Result<Vec<u64>> {
+        let reader = self.index.reader().context("failed to get index reader")?;
+        let searcher = reader.searcher();
+
+        let query_parser =
+            QueryParser::for_index(&self.index, vec![self.fields.subject, self.fields.body]);
+        let parsed_query = query_parser
+            .parse_query(query)
+            .context("failed to parse search query")?;
+
+        let top_docs = searcher
+            .search(&parsed_query, &TopDocs::with_limit(limit))
+            .context("search failed")?;
+
+        let mut rowids = Vec::with_capacity(top_docs.len());
+        for (_score, doc_address) in top_docs {
+            let doc: TantivyDocument = searcher.doc(doc_address)?;
+            if let Some(rowid) = doc
+                .get_first(self.fields.rowid)
+                .and_then(|v| v.as_u64())
+            {
+                rowids.push(rowid);
+            }
+        }
+        Ok(rowids)
+    }
+}
+
+pub struct SearchWriter {
+    writer: IndexWriter,
+    fields: SearchFields,
+    pending: usize,
+}
+
+impl SearchWriter {
+    pub fn add_document(
+        &mut self,
+        rowid: u64,
+        from_address: &str,
+        subject: &str,
+        body: &str,
+        date: i64,
+    ) -> anyhow::Result<()> {
+        let mut doc = TantivyDocument::default();
+        doc.add_u64(self.fields.rowid, rowid);
+        doc.add_text(self.fields.from_address, from_address);
+        doc.add_text(self.fields.subject, subject);
+        doc.add_text(self.fields.body, body);
+        doc.add_i64(self.fields.date, date);
+        self.writer.add_document(doc)?;
+
+        self.pending += 1;
+        if self.pending >= COMMIT_BATCH {
+            self.writer.commit().context("failed to commit search index batch")?;
+            self.pending = 0;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> anyhow::Result<()> {
+        self.writer.commit().context("failed to commit search index")?;
+        Ok(())
+    }
+}
+
+/// Converts a message's body (text and HTML parts) into a single plain-text blob suitable for
+/// full-text indexing. HTML parts are run through an HTML-to-text conversion so that HTML-only
+/// mail is still searchable.
+pub fn extract_body_text(message: &mail_parser::Message) -> String {
+    let mut text = String::new();
+
+    for i in 0..message.text_body_count() {
+        if let Some(part) = message.body_text(i) {
+            text.push_str(&part);
+            text.push('\n');
+        }
+    }
+
+    if message.text_body_count() == 0 {
+        for i in 0..message.html_body_count() {
+            if let Some(part) = message.body_html(i) {
+                text.push_str(&html2text::from_read(part.as_bytes(), usize::MAX));
+                text.push('\n');
+            }
+        }
+    }
+
+    text
+}
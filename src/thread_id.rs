@@ -0,0 +1,89 @@
+//! Union-find over `Message-ID`/`In-Reply-To`/`References` strings, used to group messages into
+//! conversation threads during indexing.
+
+use std::collections::HashMap;
+
+pub struct ThreadIndex {
+    index_of: HashMap<String, usize>,
+    ids: Vec<String>,
+    parent: Vec<usize>,
+}
+
+impl ThreadIndex {
+    pub fn new() -> Self {
+        ThreadIndex {
+            index_of: HashMap::new(),
+            ids: Vec::new(),
+            parent: Vec::new(),
+        }
+    }
+
+    /// Returns the node index for `id`, creating a new singleton set if it hasn't been seen.
+    pub fn get_or_insert(&mut self, id: &str) -> usize {
+        if let Some(&index) = self.index_of.get(id) {
+            return index;
+        }
+        let index = self.ids.len();
+        self.ids.push(id.to_owned());
+        self.parent.push(index);
+        self.index_of.insert(id.to_owned(), index);
+        index
+    }
+
+    fn find(&mut self, x: usize) -> usize {
+        if self.parent[x] != x {
+            self.parent[x] = self.find(self.parent[x]);
+        }
+        self.parent[x]
+    }
+
+    /// Unions the sets containing `a` and `b`. Always keeps whichever root sorts
+    /// lexicographically smaller, so the canonical id a set ends up with (see `thread_id`)
+    /// depends only on the set's members, not on the order `union` is called in — message
+    /// arrival order is nondeterministic across parser workers and indexing runs.
+    pub fn union(&mut self, a: usize, b: usize) {
+        let root_a = self.find(a);
+        let root_b = self.find(b);
+        if root_a == root_b {
+            return;
+        }
+        if self.ids[root_a] <= self.ids[root_b] {
+            self.parent[root_b] = root_a;
+        } else {
+            self.parent[root_a] = root_b;
+        }
+    }
+
+    /// Returns the canonical thread id (the set's representative id) for `index`.
+    pub fn thread_id(&mut self, index: usize) -> String {
+        let root = self.find(index);
+        self.ids[root].clone()
+    }
+}
+
+/// Splits a `References`/`In-Reply-To` header value into the individual `<...>` message ids it
+/// contains.
+pub fn parse_message_ids(raw: &str) -> Vec<String> {
+    raw.split_whitespace()
+        .map(|token| token.trim_matches(['<', '>', ',']))
+        .filter(|token| !token.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Strips leading `Re:`/`Fwd:`/`Fw:` reply/forward prefixes (repeated, case-insensitive) so
+/// threads display a clean subject.
+pub fn strip_reply_prefixes(subject: &str) -> &str {
+    let mut s = subject.trim();
+    loop {
+        let lower = s.to_ascii_lowercase();
+        let stripped = ["re:", "fwd:", "fw:"]
+            .iter()
+            .find_map(|prefix| lower.starts_with(prefix).then_some(&s[prefix.len()..]));
+        match stripped {
+            Some(rest) => s = rest.trim_start(),
+            None => break,
+        }
+    }
+    s
+}